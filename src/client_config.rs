@@ -0,0 +1,12 @@
+//! Client-wide configuration knobs.
+
+use crate::retry::RetryConfig;
+
+/// Configuration shared across the registry HTTP calls a client makes.
+#[derive(Debug, Clone, Default)]
+pub struct ClientConfig {
+    /// Controls retry/backoff behavior for registry HTTP calls (e.g. token
+    /// acquisition). Defaults to [`RetryConfig::default`]; use
+    /// [`RetryConfig::disabled`] to make exactly one attempt.
+    pub retry: RetryConfig,
+}
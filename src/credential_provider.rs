@@ -0,0 +1,121 @@
+//! Support for sourcing registry credentials from external providers.
+
+use crate::reference::Reference;
+use crate::token_cache::RegistryTokenType;
+use async_trait::async_trait;
+use serde::Deserialize;
+use std::process::Stdio;
+use tokio::io::AsyncWriteExt;
+use tokio::process::Command;
+use tracing::debug;
+
+/// Sentinel `Username` value a Docker credential helper uses to signal that
+/// `Secret` is an identity/refresh token rather than a password.
+const IDENTITY_TOKEN_USERNAME: &str = "<token>";
+
+/// A source of registry credentials that can be consulted when the
+/// [`TokenCache`](crate::token_cache::TokenCache) has no cached token for a
+/// reference.
+///
+/// Implementations should return `Ok(None)` when they have no credentials
+/// for the registry in question, so the caller can fall back to the next
+/// provider (or to anonymous auth) instead of failing outright.
+#[async_trait]
+pub trait CredentialProvider: Send + Sync {
+    /// Resolve credentials for the registry host backing `reference`.
+    async fn credentials(
+        &self,
+        reference: &Reference,
+    ) -> anyhow::Result<Option<RegistryTokenType>>;
+}
+
+/// The JSON document a `docker-credential-<store>` helper binary writes to
+/// stdout in response to a `get` request, per the [Docker credential-helper
+/// protocol](https://github.com/docker/docker-credential-helpers).
+#[derive(Deserialize)]
+struct CredentialHelperOutput {
+    #[serde(rename = "ServerURL")]
+    #[allow(dead_code)]
+    server_url: String,
+    #[serde(rename = "Username")]
+    username: String,
+    #[serde(rename = "Secret")]
+    secret: String,
+}
+
+/// A [`CredentialProvider`] that shells out to a Docker credential-helper
+/// binary (e.g. `docker-credential-ecr-login`) to resolve credentials.
+///
+/// This lets users authenticate against registries like ECR, GCR, and ACR
+/// using whatever helper they already have configured for the `docker` CLI,
+/// without storing secrets in their own configuration. The helper's secret
+/// is read from its output and returned in the resolved credentials.
+pub struct DockerCredentialHelper {
+    /// The helper's store name, e.g. `"ecr-login"` for the binary
+    /// `docker-credential-ecr-login`.
+    store: String,
+}
+
+impl DockerCredentialHelper {
+    /// Create a helper that shells out to `docker-credential-<store>`.
+    pub fn new(store: impl Into<String>) -> Self {
+        Self {
+            store: store.into(),
+        }
+    }
+
+    fn binary_name(&self) -> String {
+        format!("docker-credential-{}", self.store)
+    }
+}
+
+#[async_trait]
+impl CredentialProvider for DockerCredentialHelper {
+    async fn credentials(
+        &self,
+        reference: &Reference,
+    ) -> anyhow::Result<Option<RegistryTokenType>> {
+        let binary = self.binary_name();
+        let host = reference.resolve_registry();
+
+        let mut child = Command::new(&binary)
+            .arg("get")
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(|e| anyhow::anyhow!("failed to spawn credential helper `{}`: {}", binary, e))?;
+
+        child
+            .stdin
+            .take()
+            .expect("child stdin was piped")
+            .write_all(host.as_bytes())
+            .await?;
+
+        let output = child.wait_with_output().await?;
+        if !output.status.success() {
+            debug!(
+                %binary,
+                %host,
+                stderr = %String::from_utf8_lossy(&output.stderr),
+                "credential helper found no credentials for this host"
+            );
+            return Ok(None);
+        }
+
+        let parsed: CredentialHelperOutput = serde_json::from_slice(&output.stdout)
+            .map_err(|e| anyhow::anyhow!("failed to parse `{}` output: {}", binary, e))?;
+
+        if parsed.username == IDENTITY_TOKEN_USERNAME {
+            // The helper is telling us `secret` is an identity token, not a
+            // password: it's meant for the OAuth2 `refresh_token` grant, not
+            // an HTTP Basic-auth header.
+            debug!(%binary, %host, "credential helper returned an identity token");
+            return Ok(Some(RegistryTokenType::from_identity_token(parsed.secret)));
+        }
+
+        debug!(%binary, %host, "resolved credentials from credential helper");
+        Ok(Some(RegistryTokenType::Basic(parsed.username, parsed.secret)))
+    }
+}
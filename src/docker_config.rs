@@ -0,0 +1,286 @@
+//! Support for reading credentials out of the Docker CLI's `config.json`,
+//! so users don't have to re-authenticate for registries they've already
+//! run `docker login` against.
+
+use crate::credential_provider::{CredentialProvider, DockerCredentialHelper};
+use crate::reference::Reference;
+use crate::token_cache::{RegistryTokenType, TokenCache};
+use async_trait::async_trait;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::sync::Arc;
+use tracing::{debug, warn};
+
+/// Hosts that all refer to the same registry as `docker.io`, in the order
+/// Docker itself checks them.
+const DOCKER_IO_ALIASES: &[&str] = &["docker.io", "registry-1.docker.io", "index.docker.io"];
+
+/// The canonical key `auths`/`credHelpers` use for Docker Hub.
+const DOCKER_IO_CANONICAL_HOST: &str = "https://index.docker.io/v1/";
+
+/// One entry of the `auths` map in `config.json`.
+///
+/// Entries written by `docker login` against a Basic-auth registry carry
+/// `auth`; entries from an OAuth2-based login (Docker Hub, and anything
+/// behind a credential helper that issues identity tokens) instead carry
+/// `identitytoken` with no `auth`.
+#[derive(Deserialize)]
+struct AuthEntry {
+    #[serde(default)]
+    auth: Option<String>,
+    #[serde(default)]
+    identitytoken: Option<String>,
+}
+
+/// The subset of `~/.docker/config.json` this crate understands: the
+/// `auths` table `docker login` writes to, plus the `credsStore` and
+/// `credHelpers` settings that point at external credential helpers.
+#[derive(Deserialize, Default)]
+pub struct DockerConfig {
+    #[serde(default)]
+    auths: BTreeMap<String, AuthEntry>,
+    #[serde(default, rename = "credsStore")]
+    creds_store: Option<String>,
+    #[serde(default, rename = "credHelpers")]
+    cred_helpers: BTreeMap<String, String>,
+}
+
+/// A credential source resolved for a single registry host.
+enum CredentialSource {
+    /// A `user:pass` pair decoded from the `auths` map.
+    Basic(String, String),
+    /// An OAuth2 identity token from an `auths` entry that has no `auth`.
+    IdentityToken(String),
+    /// A credential helper that should be invoked for this host.
+    Helper(DockerCredentialHelper),
+}
+
+impl DockerConfig {
+    /// Load `~/.docker/config.json`. Returns `Ok(None)` if the file doesn't
+    /// exist, since that's the common case for users who have never run
+    /// `docker login`.
+    pub async fn load_default() -> anyhow::Result<Option<Self>> {
+        match dirs::home_dir() {
+            Some(home) => Self::load(&home.join(".docker").join("config.json")).await,
+            None => Ok(None),
+        }
+    }
+
+    /// Load and parse a `config.json` from a specific path.
+    pub async fn load(path: &Path) -> anyhow::Result<Option<Self>> {
+        let raw = match tokio::fs::read(path).await {
+            Ok(raw) => raw,
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => {
+                debug!(path = %path.display(), "no Docker config found");
+                return Ok(None);
+            }
+            Err(error) => return Err(error.into()),
+        };
+        Ok(Some(serde_json::from_slice(&raw)?))
+    }
+
+    /// Resolve the credential source, if any, configured for `registry`.
+    ///
+    /// `registry` is the host as it appears in an image reference (e.g.
+    /// `docker.io`, `gcr.io`), not necessarily the key Docker stores it
+    /// under in `config.json`.
+    fn resolve(&self, registry: &str) -> Option<CredentialSource> {
+        let candidates = normalized_hosts(registry);
+
+        for host in &candidates {
+            if let Some(store) = self.cred_helpers.get(host) {
+                return Some(CredentialSource::Helper(DockerCredentialHelper::new(
+                    store.clone(),
+                )));
+            }
+        }
+
+        for host in &candidates {
+            if let Some(entry) = self.auths.get(host) {
+                match (&entry.auth, &entry.identitytoken) {
+                    (Some(auth), _) => match decode_basic_auth(auth) {
+                        Ok((user, pass)) => return Some(CredentialSource::Basic(user, pass)),
+                        Err(error) => warn!(%host, %error, "failed to decode `auths` entry"),
+                    },
+                    (None, Some(identity_token)) => {
+                        return Some(CredentialSource::IdentityToken(identity_token.clone()))
+                    }
+                    (None, None) => warn!(%host, "`auths` entry has neither `auth` nor `identitytoken`"),
+                }
+            }
+        }
+
+        self.creds_store
+            .as_ref()
+            .map(|store| CredentialSource::Helper(DockerCredentialHelper::new(store.clone())))
+    }
+}
+
+/// A [`CredentialProvider`] backed by a parsed `config.json`, consulting
+/// `credHelpers`, then `auths`, then `credsStore`.
+pub struct DockerConfigCredentialProvider {
+    config: DockerConfig,
+}
+
+impl DockerConfigCredentialProvider {
+    /// Wrap an already-loaded `config.json`.
+    pub fn new(config: DockerConfig) -> Self {
+        Self { config }
+    }
+}
+
+#[async_trait]
+impl CredentialProvider for DockerConfigCredentialProvider {
+    async fn credentials(
+        &self,
+        reference: &Reference,
+    ) -> anyhow::Result<Option<RegistryTokenType>> {
+        let registry = reference.resolve_registry();
+        match self.config.resolve(registry) {
+            Some(CredentialSource::Basic(user, pass)) => {
+                Ok(Some(RegistryTokenType::Basic(user, pass)))
+            }
+            Some(CredentialSource::IdentityToken(identity_token)) => Ok(Some(
+                RegistryTokenType::from_identity_token(identity_token),
+            )),
+            Some(CredentialSource::Helper(helper)) => helper.credentials(reference).await,
+            None => Ok(None),
+        }
+    }
+}
+
+impl TokenCache {
+    /// Build a `TokenCache` pre-wired to `~/.docker/config.json`: on a cache
+    /// miss it resolves credentials the same way `docker login` would,
+    /// consulting `credHelpers`, then `auths`, then `credsStore`.
+    ///
+    /// If no Docker config exists, the provider is backed by an empty config
+    /// and never supplies credentials.
+    pub async fn from_docker_config(default_expiration_secs: usize) -> anyhow::Result<Self> {
+        let config = DockerConfig::load_default().await?.unwrap_or_default();
+        Ok(TokenCache::with_credential_provider(
+            default_expiration_secs,
+            Arc::new(DockerConfigCredentialProvider::new(config)),
+        ))
+    }
+}
+
+/// Expand `registry` into every host alias Docker might have stored
+/// credentials under, most-specific first.
+fn normalized_hosts(registry: &str) -> Vec<String> {
+    if DOCKER_IO_ALIASES.contains(&registry) {
+        let mut hosts: Vec<String> = DOCKER_IO_ALIASES.iter().map(|s| s.to_string()).collect();
+        hosts.push(DOCKER_IO_CANONICAL_HOST.to_string());
+        hosts
+    } else {
+        vec![registry.to_string()]
+    }
+}
+
+fn decode_basic_auth(encoded: &str) -> anyhow::Result<(String, String)> {
+    let decoded = base64::decode(encoded)?;
+    let decoded = String::from_utf8(decoded)?;
+    let (user, pass) = decoded
+        .split_once(':')
+        .ok_or_else(|| anyhow::anyhow!("`auths` entry is not in `user:pass` form"))?;
+    Ok((user.to_string(), pass.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn normalized_hosts_expands_docker_io_aliases() {
+        let hosts = normalized_hosts("docker.io");
+        assert_eq!(
+            hosts,
+            vec![
+                "docker.io",
+                "registry-1.docker.io",
+                "index.docker.io",
+                "https://index.docker.io/v1/",
+            ]
+        );
+        assert_eq!(normalized_hosts("registry-1.docker.io"), hosts);
+    }
+
+    #[test]
+    fn normalized_hosts_passes_through_other_registries() {
+        assert_eq!(normalized_hosts("gcr.io"), vec!["gcr.io".to_string()]);
+    }
+
+    #[test]
+    fn decode_basic_auth_splits_user_and_pass() {
+        let encoded = base64::encode("alice:hunter2");
+        let (user, pass) = decode_basic_auth(&encoded).expect("should decode");
+        assert_eq!(user, "alice");
+        assert_eq!(pass, "hunter2");
+    }
+
+    #[test]
+    fn decode_basic_auth_rejects_missing_colon() {
+        let encoded = base64::encode("no-colon-here");
+        assert!(decode_basic_auth(&encoded).is_err());
+    }
+
+    #[test]
+    fn decode_basic_auth_rejects_invalid_base64() {
+        assert!(decode_basic_auth("not valid base64!!").is_err());
+    }
+
+    #[test]
+    fn deserializes_a_realistic_config_json() {
+        let raw = r#"{
+            "auths": {
+                "https://index.docker.io/v1/": {
+                    "auth": "YWxpY2U6aHVudGVyMg=="
+                }
+            },
+            "credsStore": "desktop",
+            "credHelpers": {
+                "123456789.dkr.ecr.us-east-1.amazonaws.com": "ecr-login"
+            }
+        }"#;
+
+        let config: DockerConfig = serde_json::from_str(raw).expect("should deserialize");
+
+        assert_eq!(config.creds_store.as_deref(), Some("desktop"));
+        assert_eq!(
+            config
+                .cred_helpers
+                .get("123456789.dkr.ecr.us-east-1.amazonaws.com")
+                .map(String::as_str),
+            Some("ecr-login")
+        );
+        assert!(config.auths.contains_key("https://index.docker.io/v1/"));
+    }
+
+    #[test]
+    fn resolve_prefers_cred_helpers_over_creds_store() {
+        let raw = r#"{
+            "credsStore": "desktop",
+            "credHelpers": {
+                "gcr.io": "gcloud"
+            }
+        }"#;
+        let config: DockerConfig = serde_json::from_str(raw).expect("should deserialize");
+
+        assert!(matches!(
+            config.resolve("gcr.io"),
+            Some(CredentialSource::Helper(_))
+        ));
+    }
+
+    #[test]
+    fn resolve_falls_back_to_creds_store() {
+        let raw = r#"{"credsStore": "desktop"}"#;
+        let config: DockerConfig = serde_json::from_str(raw).expect("should deserialize");
+
+        assert!(matches!(
+            config.resolve("gcr.io"),
+            Some(CredentialSource::Helper(_))
+        ));
+    }
+}
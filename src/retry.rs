@@ -0,0 +1,168 @@
+//! A configurable retry/backoff layer for the HTTP calls this crate makes
+//! against a registry (token acquisition, manifest fetch, blob fetch).
+//!
+//! Only the token-acquisition call
+//! ([`crate::token_cache::exchange_refresh_token`]) is wrapped in
+//! [`with_retries`] so far; manifest and blob fetch live in the pull/push
+//! paths outside this crate slice and still need their own
+//! `with_retries(&client_config.retry, ...)` call sites to get the same
+//! `Retry-After`/backoff handling.
+
+use rand::Rng;
+use std::future::Future;
+use std::time::Duration;
+use tracing::debug;
+
+/// Controls how [`with_retries`] retries a registry request.
+///
+/// Exposed as [`ClientConfig::retry`](crate::client_config::ClientConfig::retry)
+/// so embedders can tune or disable retries (e.g. `RetryConfig::disabled()`
+/// for tests).
+#[derive(Debug, Clone)]
+pub struct RetryConfig {
+    /// Maximum number of attempts (including the first), before giving up.
+    pub max_attempts: u32,
+    /// Starting backoff for the exponential series, before jitter.
+    pub initial_backoff: Duration,
+    /// Upper bound on any single backoff, regardless of attempt count or a
+    /// registry-supplied `Retry-After`.
+    pub max_backoff: Duration,
+}
+
+impl Default for RetryConfig {
+    fn default() -> Self {
+        RetryConfig {
+            max_attempts: 3,
+            initial_backoff: Duration::from_millis(200),
+            max_backoff: Duration::from_secs(30),
+        }
+    }
+}
+
+impl RetryConfig {
+    /// A config that makes exactly one attempt and never retries.
+    pub fn disabled() -> Self {
+        RetryConfig {
+            max_attempts: 1,
+            ..Default::default()
+        }
+    }
+
+    fn backoff_for_attempt(&self, attempt: u32) -> Duration {
+        let exp = self.initial_backoff.saturating_mul(1 << attempt.min(16));
+        let capped = exp.min(self.max_backoff);
+        let jittered_millis = rand::thread_rng().gen_range(0..=capped.as_millis() as u64);
+        Duration::from_millis(jittered_millis)
+    }
+}
+
+/// Whether a failed attempt is worth retrying, and how long the caller
+/// should wait before the next one (when known in advance, e.g. from a
+/// `Retry-After` header).
+pub trait RetryableError {
+    /// Returns `true` if this error represents a transient condition
+    /// (`500`/`503`, a connection reset, `429`) that's worth retrying.
+    fn is_retryable(&self) -> bool;
+    /// The `Retry-After` delay the server asked for, if any.
+    fn retry_after(&self) -> Option<Duration>;
+}
+
+/// Retry `f` according to `config`, honoring [`RetryableError::retry_after`]
+/// when the error provides one and falling back to exponential backoff with
+/// jitter otherwise.
+pub(crate) async fn with_retries<F, Fut, T, E>(config: &RetryConfig, mut f: F) -> Result<T, E>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, E>>,
+    E: RetryableError,
+{
+    let mut attempt = 0;
+    loop {
+        match f().await {
+            Ok(value) => return Ok(value),
+            Err(error) => {
+                attempt += 1;
+                if attempt >= config.max_attempts || !error.is_retryable() {
+                    return Err(error);
+                }
+                let delay = error
+                    .retry_after()
+                    .map(|d| d.min(config.max_backoff))
+                    .unwrap_or_else(|| config.backoff_for_attempt(attempt));
+                debug!(attempt, ?delay, "retrying after transient registry error");
+                tokio::time::sleep(delay).await;
+            }
+        }
+    }
+}
+
+/// Parse a `Retry-After` header value, which is either a number of seconds
+/// (delta-seconds form) or an HTTP-date.
+pub(crate) fn parse_retry_after(value: &str) -> Option<Duration> {
+    if let Ok(secs) = value.trim().parse::<u64>() {
+        return Some(Duration::from_secs(secs));
+    }
+    let when = httpdate::parse_http_date(value.trim()).ok()?;
+    when.duration_since(std::time::SystemTime::now())
+        .ok()
+        .or(Some(Duration::from_secs(0)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_retry_after_accepts_delta_seconds() {
+        assert_eq!(parse_retry_after("120"), Some(Duration::from_secs(120)));
+    }
+
+    #[test]
+    fn parse_retry_after_accepts_http_date_in_the_future() {
+        let future = std::time::SystemTime::now() + Duration::from_secs(60);
+        let formatted = httpdate::fmt_http_date(future);
+        let parsed = parse_retry_after(&formatted).expect("should parse");
+        // Allow a little slack for the time elapsed formatting/parsing.
+        assert!(parsed.as_secs() <= 60 && parsed.as_secs() >= 58);
+    }
+
+    #[test]
+    fn parse_retry_after_clamps_past_http_date_to_zero() {
+        let past = std::time::SystemTime::now() - Duration::from_secs(60);
+        let formatted = httpdate::fmt_http_date(past);
+        assert_eq!(parse_retry_after(&formatted), Some(Duration::from_secs(0)));
+    }
+
+    #[test]
+    fn parse_retry_after_rejects_garbage() {
+        assert_eq!(parse_retry_after("not a valid value"), None);
+    }
+
+    #[test]
+    fn backoff_for_attempt_is_capped_at_max_backoff() {
+        let config = RetryConfig {
+            max_attempts: 10,
+            initial_backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_millis(500),
+        };
+        // A high attempt number would overflow the exponential series well
+        // past `max_backoff` if it weren't capped.
+        for _ in 0..20 {
+            assert!(config.backoff_for_attempt(16) <= Duration::from_millis(500));
+        }
+    }
+
+    #[test]
+    fn backoff_for_attempt_never_exceeds_the_exponential_bound() {
+        let config = RetryConfig::default();
+        for attempt in 0..5 {
+            let bound = config
+                .initial_backoff
+                .saturating_mul(1 << attempt)
+                .min(config.max_backoff);
+            for _ in 0..20 {
+                assert!(config.backoff_for_attempt(attempt) <= bound);
+            }
+        }
+    }
+}
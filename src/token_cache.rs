@@ -1,6 +1,10 @@
 //! Types for working with registry auth tokens
 
+use crate::client_config::ClientConfig;
+use crate::credential_provider::CredentialProvider;
+use crate::errors::{RegistryRequestError, TokenRequestError};
 use crate::reference::Reference;
+use crate::retry::{with_retries, RetryConfig};
 use serde::Deserialize;
 use std::collections::BTreeMap;
 use std::fmt;
@@ -18,11 +22,31 @@ pub enum RegistryToken {
     Token {
         /// The string value of the token
         token: String,
+        /// Seconds since `issued_at` that the token remains valid
+        #[serde(default)]
+        expires_in: Option<u64>,
+        /// When the registry issued the token
+        #[serde(default)]
+        issued_at: Option<chrono::DateTime<chrono::Utc>>,
+        /// An OAuth2 refresh token that can be exchanged for a new access
+        /// token without re-sending the original credentials
+        #[serde(default)]
+        refresh_token: Option<String>,
     },
     /// AccessToken value
     AccessToken {
         /// The string value of the access_token
         access_token: String,
+        /// Seconds since `issued_at` that the token remains valid
+        #[serde(default)]
+        expires_in: Option<u64>,
+        /// When the registry issued the token
+        #[serde(default)]
+        issued_at: Option<chrono::DateTime<chrono::Utc>>,
+        /// An OAuth2 refresh token that can be exchanged for a new access
+        /// token without re-sending the original credentials
+        #[serde(default)]
+        refresh_token: Option<String>,
     },
 }
 
@@ -50,6 +74,48 @@ pub enum RegistryTokenType {
     Basic(String, String),
 }
 
+impl RegistryTokenType {
+    /// Build a placeholder bearer entry for an identity/refresh token
+    /// obtained out-of-band (e.g. from a Docker credential helper or a
+    /// `config.json` `identitytoken` field), with no access token yet.
+    ///
+    /// `TokenCache::get` treats this as already expired, so it exchanges
+    /// `refresh_token` for a real access token as soon as it's given the
+    /// `WWW-Authenticate` challenge (`realm`/`service`) to exchange it
+    /// against, rather than ever handing the empty placeholder to a caller.
+    pub fn from_identity_token(refresh_token: String) -> Self {
+        RegistryTokenType::Bearer(RegistryToken::Token {
+            token: String::new(),
+            expires_in: Some(0),
+            issued_at: None,
+            refresh_token: Some(refresh_token),
+        })
+    }
+
+    /// True for a [`from_identity_token`](Self::from_identity_token)
+    /// placeholder: no usable access token yet, only a refresh token to
+    /// exchange for one.
+    fn is_unresolved_identity_token(&self) -> bool {
+        matches!(
+            self,
+            RegistryTokenType::Bearer(RegistryToken::Token {
+                token,
+                refresh_token: Some(_),
+                ..
+            }) if token.is_empty()
+        )
+    }
+
+    /// Returns the refresh token cached alongside this entry, if it's a
+    /// bearer token and the registry returned one
+    fn refresh_token(&self) -> Option<&str> {
+        match self {
+            RegistryTokenType::Bearer(token) => token.refresh_token(),
+            RegistryTokenType::Basic(_, _) => None,
+        }
+    }
+}
+
 impl RegistryToken {
     /// Returns the bearer token in a form suitable to use for an Authorization header
     pub fn bearer_token(&self) -> String {
@@ -58,10 +124,44 @@ impl RegistryToken {
     /// Returns the token value
     pub fn token(&self) -> &str {
         match self {
-            RegistryToken::Token { token } => token,
-            RegistryToken::AccessToken { access_token } => access_token,
+            RegistryToken::Token { token, .. } => token,
+            RegistryToken::AccessToken { access_token, .. } => access_token,
+        }
+    }
+    /// Returns the OAuth2 refresh token the registry returned alongside
+    /// this access token, if any
+    pub fn refresh_token(&self) -> Option<&str> {
+        match self {
+            RegistryToken::Token { refresh_token, .. } => refresh_token.as_deref(),
+            RegistryToken::AccessToken { refresh_token, .. } => refresh_token.as_deref(),
         }
     }
+    /// Returns the absolute expiration time (seconds since the epoch)
+    /// computed from `issued_at` and `expires_in`, if the registry supplied
+    /// both. Falls back to `issued_at` defaulting to now when only
+    /// `expires_in` is present.
+    fn expires_at(&self) -> Option<u64> {
+        let (expires_in, issued_at) = match self {
+            RegistryToken::Token {
+                expires_in,
+                issued_at,
+                ..
+            } => (*expires_in, *issued_at),
+            RegistryToken::AccessToken {
+                expires_in,
+                issued_at,
+                ..
+            } => (*expires_in, *issued_at),
+        };
+        let expires_in = expires_in?;
+        let issued_at = issued_at.map(|t| t.timestamp() as u64).unwrap_or_else(|| {
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .expect("Time went backwards")
+                .as_secs()
+        });
+        Some(issued_at + expires_in)
+    }
 }
 
 /// Desired operation for registry authentication
@@ -83,8 +183,18 @@ struct TokenCacheKey {
 struct TokenCacheValue {
     token: RegistryTokenType,
     expiration: u64,
+    /// The `WWW-Authenticate` challenge's `realm`/`service`, remembered so
+    /// an expired entry carrying a refresh token can be renewed directly
+    /// instead of repeating the full challenge. `None` for tokens that
+    /// didn't come from a bearer challenge (e.g. Basic auth).
+    realm: Option<String>,
+    service: Option<String>,
 }
 
+/// How far ahead of a token's real expiration we treat it as expired, so
+/// that in-flight requests don't race the registry's own expiry check.
+const RENEWAL_MARGIN_SECS: u64 = 30;
+
 #[derive(Default, Clone)]
 /// A cache to hold authentication tokens
 pub struct TokenCache {
@@ -92,9 +202,36 @@ pub struct TokenCache {
     tokens: Arc<RwLock<BTreeMap<TokenCacheKey, TokenCacheValue>>>,
     /// Default token expiration in seconds, to use when claim doesn't specify a value
     pub default_expiration_secs: usize,
+    /// Consulted on a cache miss, before falling back to anonymous auth.
+    credential_provider: Option<Arc<dyn CredentialProvider>>,
+    /// Used to perform the OAuth2 refresh-token exchange when a cached
+    /// entry expires.
+    http_client: reqwest::Client,
+    /// Retry/backoff behavior for the refresh-token exchange.
+    retry_config: RetryConfig,
 }
 
 impl TokenCache {
+    /// Returns a new `TokenCache` that consults `provider` for credentials
+    /// whenever it has no cached, unexpired token for a reference.
+    pub fn with_credential_provider(
+        default_expiration_secs: usize,
+        provider: Arc<dyn CredentialProvider>,
+    ) -> Self {
+        TokenCache {
+            default_expiration_secs,
+            credential_provider: Some(provider),
+            ..Default::default()
+        }
+    }
+
+    /// Returns a new `TokenCache` configured from `config`, e.g. to tune or
+    /// disable retries for the refresh-token exchange.
+    pub fn with_client_config(mut self, config: &ClientConfig) -> Self {
+        self.retry_config = config.retry.clone();
+        self
+    }
+
     /// Insert a token corresponding to reference and operation keys
     pub async fn insert(
         &self,
@@ -102,9 +239,79 @@ impl TokenCache {
         op: RegistryOperation,
         token: RegistryTokenType,
     ) {
-        let expiration = match token {
-            RegistryTokenType::Basic(_, _) => u64::MAX,
-            RegistryTokenType::Bearer(ref t) => {
+        let expiration = match self.compute_expiration(&token) {
+            Some(expiration) => expiration,
+            None => return,
+        };
+        self.insert_with_expiration(reference, op, token, expiration, None, None)
+            .await;
+    }
+
+    /// Insert a bearer token obtained via a `WWW-Authenticate` challenge,
+    /// remembering the challenge's `realm`/`service` so that, if this token
+    /// carries a refresh token, [`get`](Self::get) can renew it directly
+    /// once it expires instead of repeating the full challenge.
+    pub(crate) async fn insert_with_challenge(
+        &self,
+        reference: &Reference,
+        op: RegistryOperation,
+        token: RegistryToken,
+        realm: impl Into<String>,
+        service: impl Into<String>,
+    ) {
+        let token = RegistryTokenType::Bearer(token);
+        let expiration = match self.compute_expiration(&token) {
+            Some(expiration) => expiration,
+            None => return,
+        };
+        self.insert_with_expiration(
+            reference,
+            op,
+            token,
+            expiration,
+            Some(realm.into()),
+            Some(service.into()),
+        )
+        .await;
+    }
+
+    async fn insert_with_expiration(
+        &self,
+        reference: &Reference,
+        op: RegistryOperation,
+        token: RegistryTokenType,
+        expiration: u64,
+        realm: Option<String>,
+        service: Option<String>,
+    ) {
+        let registry = reference.resolve_registry().to_string();
+        let repository = reference.repository().to_string();
+        debug!(%registry, %repository, ?op, %expiration, "Inserting token");
+        self.tokens.write().await.insert(
+            TokenCacheKey {
+                registry,
+                repository,
+                operation: op,
+            },
+            TokenCacheValue {
+                token,
+                expiration,
+                realm,
+                service,
+            },
+        );
+    }
+
+    /// Compute the absolute expiration (seconds since the epoch) for a
+    /// token about to be inserted, or `None` if the token is an invalid
+    /// bearer token that shouldn't be cached at all.
+    fn compute_expiration(&self, token: &RegistryTokenType) -> Option<u64> {
+        match token {
+            RegistryTokenType::Basic(_, _) => Some(u64::MAX),
+            RegistryTokenType::Bearer(t) => {
+                if let Some(expiration) = t.expires_at() {
+                    return Some(expiration);
+                }
                 let token_str = t.token();
                 match jwt::Token::<
                         jwt::header::Header,
@@ -112,7 +319,7 @@ impl TokenCache {
                         jwt::token::Unverified,
                     >::parse_unverified(token_str)
                     {
-                        Ok(token) => token.claims().registered.expiration.unwrap_or(u64::MAX),
+                        Ok(token) => Some(token.claims().registered.expiration.unwrap_or(u64::MAX)),
                         Err(jwt::Error::NoClaimsComponent) => {
                             // the token doesn't have a claim that states a
                             // value for the expiration. We assume it has a 60
@@ -129,32 +336,33 @@ impl TokenCache {
                                 .as_secs();
                             let expiration = epoch + self.default_expiration_secs as u64;
                             debug!(?token, "Cannot extract expiration from token's claims, assuming a {} seconds validity", self.default_expiration_secs);
-                            expiration
+                            Some(expiration)
                         },
                         Err(error) => {
                             warn!(?error, "Invalid bearer token");
-                            return;
+                            None
                         }
                     }
             }
-        };
-        let registry = reference.resolve_registry().to_string();
-        let repository = reference.repository().to_string();
-        debug!(%registry, %repository, ?op, %expiration, "Inserting token");
-        self.tokens.write().await.insert(
-            TokenCacheKey {
-                registry,
-                repository,
-                operation: op,
-            },
-            TokenCacheValue { token, expiration },
-        );
+        }
     }
 
+    /// Look up a cached token for `reference`/`op`, renewing or fetching one
+    /// as needed.
+    ///
+    /// `challenge` is the `realm`/`service` of the `WWW-Authenticate`
+    /// challenge the caller's request was rejected with, if it has one to
+    /// offer (e.g. from a just-received 401). It's used to fill in
+    /// `realm`/`service` for entries that don't already have them cached
+    /// (identity tokens sourced from a [`CredentialProvider`], which has no
+    /// way to know a registry's token endpoint on its own), so a refresh
+    /// token can be exchanged for a real access token instead of being
+    /// handed back to the caller unresolved.
     pub(crate) async fn get(
         &self,
         reference: &Reference,
         op: RegistryOperation,
+        challenge: Option<(&str, &str)>,
     ) -> Option<RegistryTokenType> {
         let registry = reference.resolve_registry().to_string();
         let repository = reference.repository().to_string();
@@ -163,28 +371,295 @@ impl TokenCache {
             repository,
             operation: op,
         };
-        match self.tokens.read().await.get(&key) {
+
+        enum Lookup {
+            Fresh(RegistryTokenType),
+            Expired {
+                refresh_token: Option<String>,
+                realm: Option<String>,
+                service: Option<String>,
+            },
+            Missing,
+        }
+
+        let lookup = match self.tokens.read().await.get(&key) {
             Some(TokenCacheValue {
-                ref token,
+                token,
                 expiration,
+                realm,
+                service,
             }) => {
                 let now = SystemTime::now();
                 let epoch = now
                     .duration_since(UNIX_EPOCH)
                     .expect("Time went backwards")
                     .as_secs();
-                if epoch > *expiration {
+                if epoch + RENEWAL_MARGIN_SECS > *expiration {
                     debug!(%key.registry, %key.repository, ?key.operation, %expiration, miss=false, expired=true, "Fetching token");
-                    None
+                    Lookup::Expired {
+                        refresh_token: token.refresh_token().map(str::to_string),
+                        realm: realm.clone(),
+                        service: service.clone(),
+                    }
                 } else {
                     debug!(%key.registry, %key.repository, ?key.operation, %expiration, miss=false, expired=false, "Fetching token");
-                    Some(token.clone())
+                    Lookup::Fresh(token.clone())
                 }
             }
             None => {
                 debug!(%key.registry, %key.repository, ?key.operation, miss = true, "Fetching token");
+                Lookup::Missing
+            }
+        };
+
+        match lookup {
+            Lookup::Fresh(token) => return Some(token),
+            Lookup::Expired {
+                refresh_token: Some(refresh_token),
+                realm,
+                service,
+            } => {
+                let realm = realm.as_deref().or_else(|| challenge.map(|(r, _)| r));
+                let service = service.as_deref().or_else(|| challenge.map(|(_, s)| s));
+                if let (Some(realm), Some(service)) = (realm, service) {
+                    match exchange_refresh_token(
+                        &self.http_client,
+                        &self.retry_config,
+                        realm,
+                        service,
+                        &refresh_token,
+                    )
+                    .await
+                    {
+                        Ok(fresh) => {
+                            self.insert_with_challenge(reference, op, fresh.clone(), realm, service)
+                                .await;
+                            return Some(RegistryTokenType::Bearer(fresh));
+                        }
+                        Err(error) => {
+                            if error.should_reauthenticate() {
+                                debug!(%key.registry, %key.repository, ?key.operation, %error, "refresh token rejected, invalidating cached entry");
+                                self.invalidate(reference, op).await;
+                            } else {
+                                warn!(%key.registry, %key.repository, ?key.operation, %error, "failed to refresh token, falling back to full re-authentication");
+                            }
+                        }
+                    }
+                }
+            }
+            Lookup::Expired { .. } | Lookup::Missing => {}
+        }
+
+        // Nothing usable in the cache: give the configured credential
+        // provider (e.g. a Docker credential helper) a chance to supply a
+        // token before the caller falls back to anonymous auth.
+        let provider = self.credential_provider.as_ref()?;
+        match provider.credentials(reference).await {
+            Ok(Some(token)) if token.is_unresolved_identity_token() => {
+                let refresh_token = token.refresh_token().expect("checked above").to_string();
+                match challenge {
+                    Some((realm, service)) => {
+                        match exchange_refresh_token(
+                            &self.http_client,
+                            &self.retry_config,
+                            realm,
+                            service,
+                            &refresh_token,
+                        )
+                        .await
+                        {
+                            Ok(fresh) => {
+                                self.insert_with_challenge(
+                                    reference,
+                                    op,
+                                    fresh.clone(),
+                                    realm,
+                                    service,
+                                )
+                                .await;
+                                Some(RegistryTokenType::Bearer(fresh))
+                            }
+                            Err(error) => {
+                                warn!(%key.registry, %key.repository, ?key.operation, %error, "failed to exchange credential provider's identity token");
+                                None
+                            }
+                        }
+                    }
+                    None => {
+                        warn!(%key.registry, %key.repository, ?key.operation, "credential provider returned an identity token, but no WWW-Authenticate challenge was given to exchange it against");
+                        None
+                    }
+                }
+            }
+            Ok(Some(token)) => {
+                self.insert(reference, op, token.clone()).await;
+                Some(token)
+            }
+            Ok(None) => None,
+            Err(error) => {
+                warn!(%key.registry, %key.repository, ?key.operation, %error, "credential provider failed");
                 None
             }
         }
     }
+
+    /// Remove a cached token for `reference`/`op`, so the next [`get`](Self::get)
+    /// is a miss.
+    ///
+    /// Callers should do this when a registry rejects a token with
+    /// `UNAUTHORIZED`/`DENIED` before its computed expiration, and then
+    /// re-authenticate once, rather than failing outright.
+    pub(crate) async fn invalidate(&self, reference: &Reference, op: RegistryOperation) {
+        let key = TokenCacheKey {
+            registry: reference.resolve_registry().to_string(),
+            repository: reference.repository().to_string(),
+            operation: op,
+        };
+        self.tokens.write().await.remove(&key);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn expires_at_sums_issued_at_and_expires_in() {
+        let issued_at = chrono::Utc.timestamp_opt(1_000, 0).unwrap();
+        let token = RegistryToken::Token {
+            token: "t".to_string(),
+            expires_in: Some(60),
+            issued_at: Some(issued_at),
+            refresh_token: None,
+        };
+        assert_eq!(token.expires_at(), Some(1_060));
+    }
+
+    #[test]
+    fn expires_at_defaults_issued_at_to_now() {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .expect("Time went backwards")
+            .as_secs();
+        let token = RegistryToken::AccessToken {
+            access_token: "t".to_string(),
+            expires_in: Some(30),
+            issued_at: None,
+            refresh_token: None,
+        };
+        let expires_at = token.expires_at().expect("should compute an expiration");
+        assert!(expires_at >= now + 30 && expires_at <= now + 31);
+    }
+
+    #[test]
+    fn expires_at_is_none_without_expires_in() {
+        let token = RegistryToken::Token {
+            token: "t".to_string(),
+            expires_in: None,
+            issued_at: None,
+            refresh_token: None,
+        };
+        assert_eq!(token.expires_at(), None);
+    }
+
+    struct StubProvider(RegistryTokenType);
+
+    #[async_trait::async_trait]
+    impl CredentialProvider for StubProvider {
+        async fn credentials(
+            &self,
+            _reference: &Reference,
+        ) -> anyhow::Result<Option<RegistryTokenType>> {
+            Ok(Some(self.0.clone()))
+        }
+    }
+
+    #[tokio::test]
+    async fn get_does_not_return_an_unresolved_identity_token_without_a_challenge() {
+        let cache = TokenCache::with_credential_provider(
+            60,
+            Arc::new(StubProvider(RegistryTokenType::from_identity_token(
+                "a-refresh-token".to_string(),
+            ))),
+        );
+        let reference = Reference::try_from("registry.example.com/library/test:latest").unwrap();
+
+        // No WWW-Authenticate challenge was given to exchange the identity
+        // token against, so `get` must not hand back the empty placeholder.
+        let token = cache.get(&reference, RegistryOperation::Pull, None).await;
+
+        assert!(token.is_none());
+    }
+
+    #[tokio::test]
+    async fn get_returns_and_caches_an_ordinary_provider_token() {
+        let cache = TokenCache::with_credential_provider(
+            60,
+            Arc::new(StubProvider(RegistryTokenType::Basic(
+                "alice".to_string(),
+                "hunter2".to_string(),
+            ))),
+        );
+        let reference = Reference::try_from("registry.example.com/library/test:latest").unwrap();
+
+        let token = cache
+            .get(&reference, RegistryOperation::Pull, None)
+            .await
+            .expect("provider returned a token");
+        assert!(matches!(token, RegistryTokenType::Basic(_, _)));
+        assert_eq!(cache.tokens.read().await.len(), 1);
+
+        // A second lookup should be served from the cache, not the
+        // provider, now that the token has been inserted.
+        let cached = cache
+            .get(&reference, RegistryOperation::Pull, None)
+            .await
+            .expect("token should now be cached");
+        assert!(matches!(cached, RegistryTokenType::Basic(_, _)));
+    }
+}
+
+/// Exchange a refresh token for a new access token via the OAuth2
+/// `refresh_token` grant, so a client can renew a token without re-sending
+/// the user's original credentials.
+///
+/// `realm` and `service` come from the same `WWW-Authenticate` challenge
+/// that the original token request used.
+pub(crate) async fn exchange_refresh_token(
+    http_client: &reqwest::Client,
+    retry_config: &RetryConfig,
+    realm: &str,
+    service: &str,
+    refresh_token: &str,
+) -> Result<RegistryToken, TokenRequestError> {
+    with_retries(retry_config, || async {
+        let response = http_client
+            .post(realm)
+            .form(&[
+                ("grant_type", "refresh_token"),
+                ("service", service),
+                ("refresh_token", refresh_token),
+            ])
+            .send()
+            .await?;
+
+        let status = response.status();
+        if status.is_success() {
+            return Ok(response.json().await?);
+        }
+
+        let retry_after = response
+            .headers()
+            .get(reqwest::header::RETRY_AFTER)
+            .and_then(|value| value.to_str().ok())
+            .map(str::to_string);
+        let body = response.bytes().await.unwrap_or_default();
+        Err(TokenRequestError::Response(RegistryRequestError::new(
+            status.as_u16(),
+            retry_after.as_deref(),
+            &body,
+        )))
+    })
+    .await
 }